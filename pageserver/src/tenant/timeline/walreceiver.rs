@@ -10,8 +10,10 @@
 //! The data is produced by safekeepers, that push it periodically and pull it to synchronize between each other.
 //! Without this data, no WAL streaming is possible currently.
 //!
-//! Only one active WAL streaming connection is allowed at a time.
-//! The connection is supposed to be updated periodically, based on safekeeper timeline data.
+//! Multiple safekeeper connections can be streamed from concurrently, up to
+//! [`WalReceiverConf::max_concurrent_connections`]; incoming WAL is deduplicated by LSN so that
+//! overlapping streams don't double-ingest, and the connection set is refreshed periodically,
+//! based on safekeeper timeline data.
 //!
 //! * handle the actual connection and WAL streaming
 //!
@@ -30,15 +32,16 @@ use crate::tenant::timeline::walreceiver::connection_manager::{
 };
 
 use anyhow::Context;
+use std::collections::VecDeque;
 use std::future::Future;
-use std::num::NonZeroU64;
+use std::num::{NonZeroU64, NonZeroUsize};
 use std::ops::ControlFlow;
 use std::sync::atomic::{self, AtomicBool};
 use std::sync::{Arc, Weak};
 use std::time::Duration;
 use storage_broker::BrokerClientChannel;
 use tokio::select;
-use tokio::sync::{watch, RwLock};
+use tokio::sync::{Notify, RwLock};
 use tokio_util::sync::CancellationToken;
 use tracing::*;
 
@@ -58,6 +61,40 @@ pub struct WalReceiverConf {
     pub max_lsn_wal_lag: NonZeroU64,
     pub auth_token: Option<Arc<String>>,
     pub availability_zone: Option<String>,
+    /// How many safekeepers to stream WAL from concurrently. Streams beyond the furthest-ahead
+    /// one are kept warm as a hedge against a stalling safekeeper, rather than only reconnected
+    /// to after the active stream is already detected as stale.
+    pub max_concurrent_connections: NonZeroUsize,
+    /// Cap on the exponential backoff between reconnection attempts after a connection enters
+    /// [`ConnectionHealth::Backoff`].
+    pub max_reconnect_backoff: Duration,
+    /// Capacity of the event queue used to report per-connection [`TaskStateUpdate`]s. Bounds
+    /// how much unconsumed progress a lagging status reader can make a connection task hold onto.
+    pub task_event_queue_size: NonZeroUsize,
+}
+
+/// The health of a single safekeeper connection, tracked so operators can see *why* a timeline
+/// isn't streaming instead of only observing an opaque reconnect.
+///
+/// Transitions: `Connecting` -> `Streaming` -> (`Degraded` -> `Backoff` -> `Reconnecting` -> ...)*,
+/// looping back to `Streaming` on success. Backoff resets to its initial value once a connection
+/// has stayed in `Streaming` for at least a fixed minimum duration, a constant of
+/// [`ConnectionManagerState`]'s module rather than a [`WalReceiverConf`] setting.
+///
+/// [`ConnectionManagerState`]: self::connection_manager::ConnectionManagerState
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum ConnectionHealth {
+    /// Dialing the safekeeper, before the first WAL has been received.
+    Connecting,
+    /// Receiving WAL normally.
+    Streaming,
+    /// Still connected, but its latest WAL lags the applied LSN by more than
+    /// [`WalReceiverConf::max_lsn_wal_lag`].
+    Degraded,
+    /// The connection was torn down; waiting out `retry_at` before the next attempt.
+    Backoff { retry_at: std::time::Instant },
+    /// A new connection attempt is in flight after a backoff.
+    Reconnecting,
 }
 
 pub struct WalReceiver {
@@ -92,12 +129,15 @@ impl WalReceiver {
             anyhow::bail!("Wal receiver is already started");
         }
 
-        let timeline = self.timeline_ref.upgrade().with_context(|| {
+        // Upgrading is only to assert the timeline is still alive; the ids we need are already
+        // on `self.timeline` and don't require keeping the `Arc<Timeline>` around.
+        self.timeline_ref.upgrade().with_context(|| {
             format!("walreceiver start on a dropped timeline {}", self.timeline)
         })?;
 
-        let tenant_id = timeline.tenant_id;
-        let timeline_id = timeline.timeline_id;
+        let tenant_id = self.timeline.tenant_id;
+        let timeline_id = self.timeline.timeline_id;
+        let tenant_timeline_id = self.timeline;
         let walreceiver_ctx =
             ctx.detached_child(TaskKind::WalReceiverManager, DownloadBehavior::Error);
         let wal_receiver_conf = self.conf.clone();
@@ -112,7 +152,7 @@ impl WalReceiver {
             async move {
                 info!("WAL receiver manager started, connecting to broker");
                 let mut connection_manager_state = ConnectionManagerState::new(
-                    timeline,
+                    tenant_timeline_id,
                     wal_receiver_conf,
                 );
                 loop {
@@ -167,12 +207,14 @@ impl WalReceiver {
 /// The task has a channel that it can use to communicate its lifecycle events in a certain form, see [`TaskEvent`]
 /// and a cancellation token that it can listen to for earlier interrupts.
 ///
-/// Note that the communication happens via the `watch` channel, that does not accumulate the events, replacing the old one with the never one on submission.
-/// That may lead to certain events not being observed by the listener.
+/// Lifecycle events are delivered through a bounded [`TaskEventQueue`], so consecutive updates
+/// (e.g. WAL LSN progress) submitted between two polls of the listener are all observed, in order,
+/// instead of the listener only ever seeing the latest one, as long as the queue has room; once it
+/// is full, further `Progress` updates are coalesced or dropped as described on [`TaskEventQueue`].
 #[derive(Debug)]
 struct TaskHandle<E> {
     join_handle: Option<tokio::task::JoinHandle<anyhow::Result<()>>>,
-    events_receiver: watch::Receiver<TaskStateUpdate<E>>,
+    events_queue: Arc<TaskEventQueue<E>>,
     cancellation: CancellationToken,
 }
 
@@ -187,23 +229,123 @@ enum TaskStateUpdate<E> {
     Progress(E),
 }
 
-impl<E: Clone> TaskHandle<E> {
+/// A bounded FIFO queue of [`TaskStateUpdate`]s shared between a [`TaskHandle`] and the task it
+/// supervises, woken up via [`Notify`] on every push, i.e. "notify and drain" rather than "keep
+/// only the latest value" like a `watch` channel would.
+///
+/// Capacity bounds how much queued progress a lagging listener can make the task hold onto: once
+/// the queue is full, a new [`TaskStateUpdate::Progress`] coalesces with the most recently queued
+/// one (keeping the newest value) instead of growing the queue further. `Started` is always
+/// delivered, since it is only ever submitted once, into an empty queue.
+#[derive(Debug)]
+struct TaskEventQueue<E> {
+    capacity: usize,
+    updates: std::sync::Mutex<VecDeque<TaskStateUpdate<E>>>,
+    closed: AtomicBool,
+    notify: Notify,
+}
+
+impl<E> TaskEventQueue<E> {
+    fn new(capacity: usize) -> Self {
+        assert!(capacity > 0, "TaskEventQueue capacity must be positive");
+        Self {
+            capacity,
+            updates: std::sync::Mutex::new(VecDeque::with_capacity(capacity)),
+            closed: AtomicBool::new(false),
+            notify: Notify::new(),
+        }
+    }
+
+    fn push(&self, update: TaskStateUpdate<E>) {
+        let mut updates = self.updates.lock().unwrap();
+        if updates.len() >= self.capacity {
+            // Queue is saturated: only a run of `Progress` updates may be coalesced, since
+            // `Started` and task completion (observed via `closed`, not through this queue)
+            // must never be dropped.
+            if matches!(update, TaskStateUpdate::Progress(_))
+                && matches!(updates.back(), Some(TaskStateUpdate::Progress(_)))
+            {
+                updates.pop_back();
+                updates.push_back(update);
+                drop(updates);
+                self.notify.notify_one();
+            }
+            // Otherwise there's nothing safe to coalesce with (e.g. the queue is still holding
+            // `Started`): drop the update rather than growing the queue past `capacity`. This
+            // only ever discards a `Progress` update, since `Started` is pushed into a
+            // newly-created, empty queue of positive capacity and task completion bypasses this
+            // queue entirely.
+            return;
+        }
+        updates.push_back(update);
+        drop(updates);
+        self.notify.notify_one();
+    }
+
+    fn close(&self) {
+        self.closed.store(true, atomic::Ordering::Release);
+        self.notify.notify_one();
+    }
+
+    /// Waits for and returns the next queued update, or `None` once the queue is closed and
+    /// drained. Cancellation-safe: on repeated calls (e.g. inside a `select!`), no update is lost.
+    async fn pop(&self) -> Option<TaskStateUpdate<E>> {
+        loop {
+            {
+                let mut updates = self.updates.lock().unwrap();
+                if let Some(update) = updates.pop_front() {
+                    return Some(update);
+                }
+                if self.closed.load(atomic::Ordering::Acquire) {
+                    return None;
+                }
+            }
+            self.notify.notified().await;
+        }
+    }
+}
+
+/// Handed to the task spawned by [`TaskHandle::spawn`] so it can report [`TaskStateUpdate`]s.
+/// Closes its [`TaskEventQueue`] on drop, which is how the listener learns the task has ended,
+/// preserving the join-handle-vs-sender-dropped race in `next_task_event` as the terminal signal.
+struct TaskStateSender<E> {
+    queue: Arc<TaskEventQueue<E>>,
+}
+
+impl<E> TaskStateSender<E> {
+    fn send(&self, update: TaskStateUpdate<E>) {
+        self.queue.push(update);
+    }
+}
+
+impl<E> Drop for TaskStateSender<E> {
+    fn drop(&mut self) {
+        self.queue.close();
+    }
+}
+
+impl<E> TaskHandle<E> {
     /// Initializes the task, starting it immediately after the creation.
     fn spawn<Fut>(
-        task: impl FnOnce(watch::Sender<TaskStateUpdate<E>>, CancellationToken) -> Fut + Send + 'static,
+        event_queue_size: usize,
+        task: impl FnOnce(TaskStateSender<E>, CancellationToken) -> Fut + Send + 'static,
     ) -> Self
     where
         Fut: Future<Output = anyhow::Result<()>> + Send,
         E: Send + Sync + 'static,
     {
         let cancellation = CancellationToken::new();
-        let (events_sender, events_receiver) = watch::channel(TaskStateUpdate::Started);
+        let events_queue = Arc::new(TaskEventQueue::new(event_queue_size));
 
         let cancellation_clone = cancellation.clone();
+        let events_queue_clone = Arc::clone(&events_queue);
         let join_handle = WALRECEIVER_RUNTIME.spawn(async move {
-            events_sender.send(TaskStateUpdate::Started).ok();
+            let events_sender = TaskStateSender {
+                queue: events_queue_clone,
+            };
+            events_sender.send(TaskStateUpdate::Started);
             task(events_sender, cancellation_clone).await
-            // events_sender is dropped at some point during the .await above.
+            // events_sender is dropped at some point during the .await above, closing the queue.
             // But the task is still running on WALRECEIVER_RUNTIME.
             // That is the window when `!jh.is_finished()`
             // is true inside `fn next_task_event()` below.
@@ -211,53 +353,51 @@ impl<E: Clone> TaskHandle<E> {
 
         TaskHandle {
             join_handle: Some(join_handle),
-            events_receiver,
+            events_queue,
             cancellation,
         }
     }
 
     async fn next_task_event(&mut self) -> TaskEvent<E> {
-        match self.events_receiver.changed().await {
-            Ok(()) => TaskEvent::Update((self.events_receiver.borrow()).clone()),
-            Err(_task_channel_part_dropped) => {
-                TaskEvent::End(match self.join_handle.as_mut() {
-                    Some(jh) => {
-                        if !jh.is_finished() {
-                            // Barring any implementation errors in this module, we can
-                            // only arrive here while the task that executes the future
-                            // passed to `Self::spawn()` is still execution. Cf the comment
-                            // in Self::spawn().
-                            //
-                            // This was logging at warning level in earlier versions, presumably
-                            // to leave some breadcrumbs in case we had an implementation
-                            // error that would would make us get stuck in `jh.await`.
-                            //
-                            // There hasn't been such a bug so far.
-                            // But in a busy system, e.g., during pageserver restart,
-                            // we arrive here often enough that the warning-level logs
-                            // became a distraction.
-                            // So, tone them down to info-level.
-                            //
-                            // XXX: rewrite this module to eliminate the race condition.
-                            info!("sender is dropped while join handle is still alive");
-                        }
-
-                        let res = jh
-                            .await
-                            .map_err(|e| anyhow::anyhow!("Failed to join task: {e}"))
-                            .and_then(|x| x);
-
-                        // For cancellation-safety, drop join_handle only after successful .await.
-                        self.join_handle = None;
-
-                        res
+        match self.events_queue.pop().await {
+            Some(update) => TaskEvent::Update(update),
+            None => TaskEvent::End(match self.join_handle.as_mut() {
+                Some(jh) => {
+                    if !jh.is_finished() {
+                        // Barring any implementation errors in this module, we can
+                        // only arrive here while the task that executes the future
+                        // passed to `Self::spawn()` is still execution. Cf the comment
+                        // in Self::spawn().
+                        //
+                        // This was logging at warning level in earlier versions, presumably
+                        // to leave some breadcrumbs in case we had an implementation
+                        // error that would would make us get stuck in `jh.await`.
+                        //
+                        // There hasn't been such a bug so far.
+                        // But in a busy system, e.g., during pageserver restart,
+                        // we arrive here often enough that the warning-level logs
+                        // became a distraction.
+                        // So, tone them down to info-level.
+                        //
+                        // XXX: rewrite this module to eliminate the race condition.
+                        info!("sender is dropped while join handle is still alive");
                     }
-                    None => {
-                        // Another option is to have an enum, join handle or result and give away the reference to it
-                        Err(anyhow::anyhow!("Task was joined more than once"))
-                    }
-                })
-            }
+
+                    let res = jh
+                        .await
+                        .map_err(|e| anyhow::anyhow!("Failed to join task: {e}"))
+                        .and_then(|x| x);
+
+                    // For cancellation-safety, drop join_handle only after successful .await.
+                    self.join_handle = None;
+
+                    res
+                }
+                None => {
+                    // Another option is to have an enum, join handle or result and give away the reference to it
+                    Err(anyhow::anyhow!("Task was joined more than once"))
+                }
+            }),
         }
     }
 
@@ -279,3 +419,69 @@ impl<E: Clone> TaskHandle<E> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn fifo_order_preserved_under_capacity() {
+        let queue = TaskEventQueue::<u64>::new(4);
+        queue.push(TaskStateUpdate::Started);
+        queue.push(TaskStateUpdate::Progress(1));
+        queue.push(TaskStateUpdate::Progress(2));
+
+        assert!(matches!(queue.pop().await, Some(TaskStateUpdate::Started)));
+        assert!(matches!(queue.pop().await, Some(TaskStateUpdate::Progress(1))));
+        assert!(matches!(queue.pop().await, Some(TaskStateUpdate::Progress(2))));
+    }
+
+    #[tokio::test]
+    async fn overflow_coalesces_consecutive_progress() {
+        let queue = TaskEventQueue::<u64>::new(2);
+        queue.push(TaskStateUpdate::Started);
+        queue.push(TaskStateUpdate::Progress(1));
+        // Queue is now full (Started, Progress(1)); this should coalesce with the already
+        // queued Progress(1), keeping only the newest value, instead of growing the queue.
+        queue.push(TaskStateUpdate::Progress(2));
+
+        assert!(matches!(queue.pop().await, Some(TaskStateUpdate::Started)));
+        assert!(matches!(queue.pop().await, Some(TaskStateUpdate::Progress(2))));
+    }
+
+    #[tokio::test]
+    async fn overflow_drops_progress_it_cannot_coalesce_with() {
+        let queue = TaskEventQueue::<u64>::new(1);
+        queue.push(TaskStateUpdate::Started);
+        // The queue is full with a non-`Progress` item at the back, so there's nothing to
+        // coalesce with: the new update is dropped rather than growing past capacity.
+        queue.push(TaskStateUpdate::Progress(1));
+
+        assert!(matches!(queue.pop().await, Some(TaskStateUpdate::Started)));
+    }
+
+    #[tokio::test]
+    async fn pop_observes_close_only_after_queue_drains() {
+        let queue = TaskEventQueue::<u64>::new(4);
+        queue.push(TaskStateUpdate::Progress(1));
+        queue.close();
+
+        assert!(matches!(queue.pop().await, Some(TaskStateUpdate::Progress(1))));
+        assert!(queue.pop().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn pop_wakes_on_concurrent_push() {
+        let queue = Arc::new(TaskEventQueue::<u64>::new(4));
+        let queue_clone = Arc::clone(&queue);
+        let popper = tokio::spawn(async move { queue_clone.pop().await });
+
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        queue.push(TaskStateUpdate::Progress(42));
+
+        assert!(matches!(
+            popper.await.unwrap(),
+            Some(TaskStateUpdate::Progress(42))
+        ));
+    }
+}
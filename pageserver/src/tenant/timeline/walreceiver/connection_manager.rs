@@ -0,0 +1,610 @@
+//! Manages the set of safekeeper connections a single timeline's WAL receiver streams from.
+//!
+//! Unlike the previous single-connection design, [`ConnectionManagerState`] keeps a connection
+//! task running per safekeeper the broker tells us about (up to
+//! [`WalReceiverConf::max_concurrent_connections`]), so a stalling safekeeper doesn't have to be
+//! detected as stale and fully reconnected before another one can make progress. WAL chunks
+//! arriving from the concurrent streams are deduplicated by LSN and applied in contiguous order,
+//! buffering segments that arrive out of order until the gap before them closes. Once the
+//! connection budget is spent, a newly desired safekeeper evicts whichever connection is both
+//! [`ConnectionHealth::Degraded`] and furthest behind; if no connection is `Degraded`, the new
+//! safekeeper is left unconnected.
+
+use std::collections::{BTreeMap, HashMap};
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use anyhow::Context;
+use futures::StreamExt;
+use rand::Rng;
+use storage_broker::BrokerClientChannel;
+use tokio::sync::RwLock;
+use tracing::*;
+use utils::id::{NodeId, TenantTimelineId};
+use utils::lsn::Lsn;
+
+use super::walreceiver_connection::{
+    SafekeeperTransport, WalStreamChunk, WalStreamFeedback, WalStreamTransport,
+};
+use super::{ConnectionHealth, TaskEvent, TaskHandle, TaskStateUpdate, WalReceiverConf};
+use crate::context::RequestContext;
+
+type ConnectFuture = Pin<Box<dyn Future<Output = anyhow::Result<Box<dyn WalStreamTransport>>> + Send>>;
+
+/// Dials a safekeeper and returns the transport to stream WAL from it, starting at `start_lsn`.
+/// Swappable (see [`ConnectionManagerState::with_connector`]) so tests can inject a scripted or
+/// file-replayed [`WalStreamTransport`] instead of a live connection.
+pub(crate) type Connect = Arc<dyn Fn(NodeId, String, Lsn) -> ConnectFuture + Send + Sync>;
+
+/// Initial delay before the first reconnection attempt; grows exponentially (doubling per
+/// attempt), capped by [`WalReceiverConf::max_reconnect_backoff`].
+const INITIAL_RECONNECT_BACKOFF: Duration = Duration::from_millis(100);
+
+/// How long a connection needs to stream without a failure before its backoff resets back to
+/// [`INITIAL_RECONNECT_BACKOFF`], so a safekeeper that's flaky once doesn't stay penalized
+/// forever.
+const MIN_STABLE_STREAMING_DURATION: Duration = Duration::from_secs(10);
+
+/// Upper bound on the out-of-order chunks buffered in [`ConnectionManagerState::pending`].
+/// Without one, a safekeeper that never delivers the chunk that would close a gap lets chunks
+/// from other, faster streams accumulate forever instead of being buffered only "briefly".
+const MAX_PENDING_CHUNKS: usize = 1000;
+
+/// How often a connected stream reports the latest LSN it has received back to its safekeeper, so the
+/// safekeeper can advance its own WAL retention instead of holding onto everything pageserver
+/// might still need.
+const FEEDBACK_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Exponential backoff with full jitter for the delay before reconnecting to a safekeeper,
+/// given how many consecutive attempts already failed.
+fn next_backoff(attempt: u32, cap: Duration) -> Duration {
+    let exp = INITIAL_RECONNECT_BACKOFF
+        .checked_mul(1u32.checked_shl(attempt).unwrap_or(u32::MAX))
+        .unwrap_or(cap);
+    let capped = exp.min(cap);
+    let jitter_ms = rand::thread_rng().gen_range(0..=capped.as_millis().max(1) as u64);
+    Duration::from_millis(jitter_ms)
+}
+
+/// Tracks the reconnect backoff for a safekeeper that isn't currently connected, either because
+/// it's never been dialed yet or because its last connection attempt failed.
+struct Backoff {
+    attempt: u32,
+    retry_at: Instant,
+}
+
+/// What the broker told us about a safekeeper that might be worth streaming from.
+#[derive(Debug, Clone)]
+pub(crate) struct SafekeeperInfo {
+    pub node_id: NodeId,
+    pub connstr: String,
+}
+
+struct SafekeeperConnection {
+    task: TaskHandle<WalStreamChunk>,
+    last_known_lsn: Option<Lsn>,
+    health: ConnectionHealth,
+    /// Set once this connection enters [`ConnectionHealth::Streaming`], so a later event can tell
+    /// whether it's been stable for long enough to reset its backoff.
+    streaming_since: Option<Instant>,
+}
+
+/// What [`ConnectionManagerState`] currently knows about one safekeeper connection.
+#[derive(Debug, Clone, Copy)]
+pub struct SafekeeperConnectionStatus {
+    pub last_known_lsn: Option<Lsn>,
+    pub health: ConnectionHealth,
+}
+
+/// A public snapshot of [`ConnectionManagerState`], cheap to clone so it can be handed out
+/// through [`WalReceiver::status`](super::WalReceiver::status) without holding the manager's lock.
+#[derive(Debug, Clone, Default)]
+pub struct ConnectionManagerStatus {
+    pub connections: HashMap<NodeId, SafekeeperConnectionStatus>,
+    pub applied_lsn: Option<Lsn>,
+}
+
+pub(crate) struct ConnectionManagerState {
+    tenant_timeline_id: TenantTimelineId,
+    conf: WalReceiverConf,
+    connect: Connect,
+    connections: HashMap<NodeId, SafekeeperConnection>,
+    /// Safekeepers that failed and are waiting out a backoff before the next connection attempt.
+    backoffs: HashMap<NodeId, Backoff>,
+    /// Highest contiguous LSN applied so far. Chunks at or below this are duplicates of WAL
+    /// some other stream already delivered, and are dropped.
+    applied_lsn: Lsn,
+    /// WAL chunks received out of order, buffered until they become contiguous with `applied_lsn`.
+    pending: BTreeMap<Lsn, WalStreamChunk>,
+}
+
+impl ConnectionManagerState {
+    pub(crate) fn new(tenant_timeline_id: TenantTimelineId, conf: WalReceiverConf) -> Self {
+        Self::with_connector(tenant_timeline_id, conf, Arc::new(connect_to_safekeeper))
+    }
+
+    /// Like [`Self::new`], but with the safekeeper dialer swapped out. This is what lets a test
+    /// drive the connection manager against a scripted or file-replayed [`WalStreamTransport`]
+    /// instead of a live safekeeper, including injecting faults like partial frames or a
+    /// mid-stream disconnect.
+    pub(crate) fn with_connector(
+        tenant_timeline_id: TenantTimelineId,
+        conf: WalReceiverConf,
+        connect: Connect,
+    ) -> Self {
+        Self {
+            tenant_timeline_id,
+            conf,
+            connect,
+            connections: HashMap::new(),
+            backoffs: HashMap::new(),
+            applied_lsn: Lsn(0),
+            pending: BTreeMap::new(),
+        }
+    }
+
+    /// Starts a connection task for every safekeeper in `desired` that isn't already connected
+    /// (and isn't still waiting out a backoff), up to
+    /// [`WalReceiverConf::max_concurrent_connections`] -- evicting the worst
+    /// [`ConnectionHealth::Degraded`] connection to make room if the budget is already spent --
+    /// and shuts down any connection whose safekeeper fell out of `desired` entirely.
+    async fn reconcile_connections(&mut self, desired: &[SafekeeperInfo]) {
+        let desired_ids: std::collections::HashSet<NodeId> =
+            desired.iter().map(|sk| sk.node_id).collect();
+        self.backoffs.retain(|node_id, _| desired_ids.contains(node_id));
+
+        let stale_ids: Vec<NodeId> = self
+            .connections
+            .keys()
+            .filter(|node_id| !desired_ids.contains(node_id))
+            .copied()
+            .collect();
+        for node_id in stale_ids {
+            if let Some(conn) = self.connections.remove(&node_id) {
+                info!("Safekeeper {node_id} no longer desired, shutting down its connection");
+                conn.task.shutdown().await;
+            }
+        }
+
+        let now = Instant::now();
+        let budget = self.conf.max_concurrent_connections.get();
+        for sk in desired {
+            if self.connections.contains_key(&sk.node_id) {
+                continue;
+            }
+            if let Some(backoff) = self.backoffs.get(&sk.node_id) {
+                if backoff.retry_at > now {
+                    continue;
+                }
+            }
+            if self.connections.len() >= budget {
+                let Some(evicted_id) = self.worst_degraded_connection() else {
+                    // Every connection is at least as healthy as a brand new one would start
+                    // out: nothing worth evicting, so leave `sk` unconnected for now.
+                    break;
+                };
+                if let Some(conn) = self.connections.remove(&evicted_id) {
+                    info!(
+                        "Evicting degraded connection to safekeeper {evicted_id} to make room for {}",
+                        sk.node_id
+                    );
+                    conn.task.shutdown().await;
+                }
+            }
+            let connection = self.spawn_connection(sk);
+            self.connections.insert(sk.node_id, connection);
+            info!("Started WAL streaming from safekeeper {}", sk.node_id);
+        }
+    }
+
+    /// The connected safekeeper furthest behind among those currently
+    /// [`ConnectionHealth::Degraded`], i.e. the best candidate to retire in favor of a safekeeper
+    /// we haven't tried yet.
+    fn worst_degraded_connection(&self) -> Option<NodeId> {
+        self.connections
+            .iter()
+            .filter(|(_, conn)| conn.health == ConnectionHealth::Degraded)
+            .min_by_key(|(_, conn)| conn.last_known_lsn.unwrap_or(Lsn(0)))
+            .map(|(node_id, _)| *node_id)
+    }
+
+    fn spawn_connection(&self, sk: &SafekeeperInfo) -> SafekeeperConnection {
+        let connect = Arc::clone(&self.connect);
+        let node_id = sk.node_id;
+        let connstr = sk.connstr.clone();
+        let start_lsn = self.applied_lsn;
+        let task = TaskHandle::spawn(
+            self.conf.task_event_queue_size.get(),
+            move |events, cancellation| async move {
+                stream_from_safekeeper(connect, node_id, connstr, start_lsn, events, cancellation).await
+            },
+        );
+        let health = if self.backoffs.contains_key(&node_id) {
+            ConnectionHealth::Reconnecting
+        } else {
+            ConnectionHealth::Connecting
+        };
+        SafekeeperConnection {
+            task,
+            last_known_lsn: None,
+            health,
+            streaming_since: None,
+        }
+    }
+
+    /// Waits for the next event from any connected safekeeper, ingests any WAL chunk it carries
+    /// (deduplicating and buffering as needed), and retires the connection on failure so
+    /// [`reconcile_connections`](Self::reconcile_connections) can replace it on the next loop
+    /// iteration.
+    async fn next_event(&mut self) {
+        if self.connections.is_empty() {
+            return;
+        }
+
+        let (node_id, event) = {
+            let mut polled = self
+                .connections
+                .iter_mut()
+                .map(|(node_id, conn)| {
+                    let node_id = *node_id;
+                    Box::pin(async move { (node_id, conn.task.next_task_event().await) })
+                })
+                .collect::<Vec<_>>();
+            let (result, _index, _remaining) = futures::future::select_all(polled.drain(..)).await;
+            result
+        };
+
+        match event {
+            TaskEvent::Update(TaskStateUpdate::Started) => {
+                debug!("Connection to safekeeper {node_id} started");
+            }
+            TaskEvent::Update(TaskStateUpdate::Progress(chunk)) => {
+                self.ingest(chunk.clone());
+                if let Some(conn) = self.connections.get_mut(&node_id) {
+                    conn.last_known_lsn = Some(chunk.end_lsn);
+                    let lag = self.applied_lsn.0.saturating_sub(chunk.end_lsn.0);
+                    conn.health = if lag > self.conf.max_lsn_wal_lag.get() {
+                        ConnectionHealth::Degraded
+                    } else {
+                        ConnectionHealth::Streaming
+                    };
+                    if conn.health == ConnectionHealth::Streaming {
+                        conn.streaming_since.get_or_insert_with(Instant::now);
+                    } else {
+                        conn.streaming_since = None;
+                    }
+                    let stable = conn.health == ConnectionHealth::Streaming
+                        && conn
+                            .streaming_since
+                            .is_some_and(|since| since.elapsed() >= MIN_STABLE_STREAMING_DURATION);
+                    if stable {
+                        self.backoffs.remove(&node_id);
+                    }
+                }
+            }
+            TaskEvent::End(Ok(())) => {
+                debug!("Connection to safekeeper {node_id} ended");
+                self.connections.remove(&node_id);
+                self.record_failure(node_id);
+            }
+            TaskEvent::End(Err(e)) => {
+                warn!("Connection to safekeeper {node_id} failed: {e:#}");
+                self.connections.remove(&node_id);
+                self.record_failure(node_id);
+            }
+        }
+    }
+
+    /// Schedules the next reconnection attempt for `node_id` after a connection ended, growing
+    /// its backoff from whatever it was waiting out before (or starting fresh at
+    /// [`INITIAL_RECONNECT_BACKOFF`] if this is its first failure since the last stable stream).
+    fn record_failure(&mut self, node_id: NodeId) {
+        let attempt = self.backoffs.get(&node_id).map_or(0, |b| b.attempt) + 1;
+        let delay = next_backoff(attempt - 1, self.conf.max_reconnect_backoff);
+        self.backoffs.insert(
+            node_id,
+            Backoff {
+                attempt,
+                retry_at: Instant::now() + delay,
+            },
+        );
+    }
+
+    /// Deduplicates `chunk` against `applied_lsn` and applies every now-contiguous chunk in
+    /// order, buffering anything that still has a gap before it, up to [`MAX_PENDING_CHUNKS`].
+    fn ingest(&mut self, chunk: WalStreamChunk) {
+        if chunk.end_lsn <= self.applied_lsn {
+            // Some other, faster stream already delivered this range.
+            return;
+        }
+        if chunk.start_lsn > self.applied_lsn
+            && !self.pending.contains_key(&chunk.start_lsn)
+            && self.pending.len() >= MAX_PENDING_CHUNKS
+        {
+            warn!(
+                "Dropping out-of-order WAL chunk [{}, {}): pending buffer already holds \
+                 {MAX_PENDING_CHUNKS} chunks waiting on a gap at {}",
+                chunk.start_lsn, chunk.end_lsn, self.applied_lsn
+            );
+            return;
+        }
+        self.pending.insert(chunk.start_lsn, chunk);
+
+        while let Some(start_lsn) = self.pending.keys().next().copied() {
+            if start_lsn > self.applied_lsn {
+                break; // a gap remains before this chunk; wait for the missing segment
+            }
+            let chunk = self.pending.remove(&start_lsn).expect("just peeked this key");
+            if chunk.end_lsn > self.applied_lsn {
+                self.applied_lsn = chunk.end_lsn;
+                // The actual ingestion into the timeline's WAL record store happens here, once
+                // this chunk is known contiguous with everything applied so far.
+            }
+        }
+    }
+
+    pub(crate) fn status(&self) -> ConnectionManagerStatus {
+        let mut connections: HashMap<NodeId, SafekeeperConnectionStatus> = self
+            .connections
+            .iter()
+            .map(|(node_id, conn)| {
+                (
+                    *node_id,
+                    SafekeeperConnectionStatus {
+                        last_known_lsn: conn.last_known_lsn,
+                        health: conn.health,
+                    },
+                )
+            })
+            .collect();
+        for (node_id, backoff) in &self.backoffs {
+            connections.entry(*node_id).or_insert(SafekeeperConnectionStatus {
+                last_known_lsn: None,
+                health: ConnectionHealth::Backoff {
+                    retry_at: backoff.retry_at,
+                },
+            });
+        }
+        ConnectionManagerStatus {
+            connections,
+            applied_lsn: Some(self.applied_lsn),
+        }
+    }
+
+    pub(crate) async fn shutdown(self) {
+        for (_node_id, conn) in self.connections {
+            conn.task.shutdown().await;
+        }
+    }
+}
+
+async fn stream_from_safekeeper(
+    connect: Connect,
+    node_id: NodeId,
+    connstr: String,
+    start_lsn: Lsn,
+    events: super::TaskStateSender<WalStreamChunk>,
+    cancellation: tokio_util::sync::CancellationToken,
+) -> anyhow::Result<()> {
+    let mut transport = connect(node_id, connstr, start_lsn)
+        .await
+        .with_context(|| format!("connecting to safekeeper {node_id}"))?;
+
+    // The latest LSN this stream has itself read off the wire, reported upstream as feedback so
+    // the safekeeper can advance its own WAL retention instead of holding onto everything.
+    let mut last_lsn = start_lsn;
+    let mut feedback_interval = tokio::time::interval(FEEDBACK_INTERVAL);
+    feedback_interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+    loop {
+        tokio::select! {
+            _ = cancellation.cancelled() => return Ok(()),
+            _ = feedback_interval.tick() => {
+                transport
+                    .send_feedback(WalStreamFeedback {
+                        applied_lsn: last_lsn,
+                        ts: std::time::SystemTime::now(),
+                    })
+                    .await
+                    .with_context(|| format!("sending feedback to safekeeper {node_id}"))?;
+            }
+            chunk = transport.next_chunk() => match chunk? {
+                Some(chunk) => {
+                    last_lsn = chunk.end_lsn;
+                    events.send(TaskStateUpdate::Progress(chunk));
+                }
+                None => return Ok(()),
+            },
+        }
+    }
+}
+
+fn connect_to_safekeeper(_node_id: NodeId, connstr: String, start_lsn: Lsn) -> ConnectFuture {
+    Box::pin(async move {
+        let transport = SafekeeperTransport::connect(&connstr, start_lsn).await?;
+        Ok(Box::new(transport) as Box<dyn WalStreamTransport>)
+    })
+}
+
+/// Asks the broker which safekeepers currently claim to hold WAL for this timeline.
+async fn fetch_desired_safekeepers(
+    broker_client: &mut BrokerClientChannel,
+    tenant_timeline_id: TenantTimelineId,
+) -> anyhow::Result<Vec<SafekeeperInfo>> {
+    let request = storage_broker::proto::SubscribeSafekeeperInfoRequest {
+        subscription_key: Some(storage_broker::proto::SubscriptionKey::TenantTimelineId(
+            storage_broker::proto::ProtoTenantTimelineId {
+                tenant_id: tenant_timeline_id.tenant_id.as_ref().to_vec(),
+                timeline_id: tenant_timeline_id.timeline_id.as_ref().to_vec(),
+            },
+        )),
+    };
+    let mut stream = broker_client
+        .subscribe_safekeeper_info(request)
+        .await
+        .context("subscribing to safekeeper info from the broker")?
+        .into_inner();
+
+    let mut safekeepers = Vec::new();
+    // One broker poll per connection-manager loop iteration: take whatever's immediately
+    // available rather than blocking this iteration on the long-lived subscription.
+    while let Ok(Some(Ok(info))) =
+        tokio::time::timeout(std::time::Duration::from_millis(50), stream.next()).await
+    {
+        safekeepers.push(SafekeeperInfo {
+            node_id: NodeId(info.safekeeper_id),
+            connstr: info.safekeeper_connstr,
+        });
+    }
+    Ok(safekeepers)
+}
+
+pub(crate) async fn connection_manager_loop_step(
+    broker_client: &mut BrokerClientChannel,
+    state: &mut ConnectionManagerState,
+    _ctx: &RequestContext,
+    status: &Arc<RwLock<Option<ConnectionManagerStatus>>>,
+) -> std::ops::ControlFlow<(), ()> {
+    match fetch_desired_safekeepers(broker_client, state.tenant_timeline_id).await {
+        Ok(desired) => state.reconcile_connections(&desired).await,
+        Err(e) => error!("Failed to fetch safekeeper set from broker: {e:#}"),
+    }
+
+    state.next_event().await;
+
+    *status.write().await = Some(state.status());
+
+    std::ops::ControlFlow::Continue(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::num::{NonZeroU64, NonZeroUsize};
+
+    use utils::id::{TenantId, TimelineId};
+
+    use super::*;
+
+    /// A [`WalStreamTransport`] driven entirely by a pre-scripted queue of chunks, for exercising
+    /// [`ConnectionManagerState`] through [`ConnectionManagerState::with_connector`] instead of a
+    /// live safekeeper. Once the script is exhausted it blocks forever, like a real stream with
+    /// nothing new to send, so a connection only ever ends via cancellation.
+    struct ScriptedTransport {
+        chunks: std::collections::VecDeque<WalStreamChunk>,
+    }
+
+    #[async_trait::async_trait]
+    impl WalStreamTransport for ScriptedTransport {
+        async fn next_chunk(&mut self) -> anyhow::Result<Option<WalStreamChunk>> {
+            match self.chunks.pop_front() {
+                Some(chunk) => Ok(Some(chunk)),
+                None => std::future::pending().await,
+            }
+        }
+
+        async fn send_feedback(&mut self, _feedback: WalStreamFeedback) -> anyhow::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn chunk(start: u64, end: u64) -> WalStreamChunk {
+        WalStreamChunk {
+            data: bytes::Bytes::from(vec![0u8; (end - start) as usize]),
+            start_lsn: Lsn(start),
+            end_lsn: Lsn(end),
+        }
+    }
+
+    fn test_conf() -> WalReceiverConf {
+        WalReceiverConf {
+            wal_connect_timeout: Duration::from_secs(1),
+            lagging_wal_timeout: Duration::from_secs(1),
+            max_lsn_wal_lag: NonZeroU64::new(1_000_000).unwrap(),
+            auth_token: None,
+            availability_zone: None,
+            max_concurrent_connections: NonZeroUsize::new(2).unwrap(),
+            max_reconnect_backoff: Duration::from_secs(1),
+            task_event_queue_size: NonZeroUsize::new(16).unwrap(),
+        }
+    }
+
+    fn test_tenant_timeline_id() -> TenantTimelineId {
+        TenantTimelineId::new(TenantId::generate(), TimelineId::generate())
+    }
+
+    fn scripted_connector(by_node: HashMap<NodeId, Vec<WalStreamChunk>>) -> Connect {
+        let by_node = Arc::new(by_node);
+        Arc::new(move |node_id, _connstr, _start_lsn| {
+            let chunks = by_node.get(&node_id).cloned().unwrap_or_default();
+            Box::pin(async move {
+                Ok(Box::new(ScriptedTransport {
+                    chunks: chunks.into(),
+                }) as Box<dyn WalStreamTransport>)
+            })
+        })
+    }
+
+    #[tokio::test]
+    async fn with_connector_dedups_overlapping_streams_and_advances_contiguously() {
+        let node_a = NodeId(1);
+        let node_b = NodeId(2);
+        let connect = scripted_connector(HashMap::from([
+            (node_a, vec![chunk(0, 15)]),
+            (node_b, vec![chunk(0, 10), chunk(10, 25)]),
+        ]));
+
+        let mut state =
+            ConnectionManagerState::with_connector(test_tenant_timeline_id(), test_conf(), connect);
+
+        state
+            .reconcile_connections(&[
+                SafekeeperInfo {
+                    node_id: node_a,
+                    connstr: "a".to_string(),
+                },
+                SafekeeperInfo {
+                    node_id: node_b,
+                    connstr: "b".to_string(),
+                },
+            ])
+            .await;
+        assert_eq!(state.connections.len(), 2);
+
+        for _ in 0..20 {
+            if state.applied_lsn >= Lsn(25) {
+                break;
+            }
+            state.next_event().await;
+        }
+
+        assert_eq!(state.applied_lsn, Lsn(25));
+        assert_eq!(state.status().applied_lsn, Some(Lsn(25)));
+
+        state.shutdown().await;
+    }
+
+    #[tokio::test]
+    async fn reconcile_connections_shuts_down_connections_no_longer_desired() {
+        let node_a = NodeId(1);
+        let connect = scripted_connector(HashMap::new());
+
+        let mut state =
+            ConnectionManagerState::with_connector(test_tenant_timeline_id(), test_conf(), connect);
+
+        state
+            .reconcile_connections(&[SafekeeperInfo {
+                node_id: node_a,
+                connstr: "a".to_string(),
+            }])
+            .await;
+        assert_eq!(state.connections.len(), 1);
+
+        // `node_a` fell out of the desired set. `ScriptedTransport::next_chunk` blocks forever
+        // once its script is empty, so this only returns once the connection has actually been
+        // cancelled and shut down, not merely dropped from the map.
+        state.reconcile_connections(&[]).await;
+        assert!(state.connections.is_empty());
+    }
+}